@@ -0,0 +1,71 @@
+use anyhow::{Context, Result};
+use log::*;
+use serde::Deserialize;
+use sqlx::MySqlPool;
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Maps a discount code (e.g. `"a"`) to the percentage markdown it applies.
+pub type DiscountRules = HashMap<String, f32>;
+
+#[derive(Debug, Deserialize)]
+struct DiscountRule {
+    code: String,
+    percentage: f32,
+}
+
+/// Loads the discount code → percentage table.
+///
+/// If `path` is given, the rules are parsed from that TOML or JSON file
+/// (by extension) and no database access is needed. Otherwise they're read
+/// from the `discount_rules` table in the same database the rest of the
+/// tool writes to, which requires `pool` to be provided.
+pub async fn load_discount_rules(
+    pool: Option<&MySqlPool>,
+    path: Option<&Path>,
+) -> Result<DiscountRules> {
+    match (path, pool) {
+        (Some(path), _) => load_from_file(path),
+        (None, Some(pool)) => load_from_database(pool).await,
+        (None, None) => Err(anyhow::anyhow!(
+            "no discount rules source available: pass --discount-rules or provide database connectivity"
+        )),
+    }
+}
+
+fn load_from_file(path: &Path) -> Result<DiscountRules> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("failed to read discount rules file {}", path.display()))?;
+
+    let rules: Vec<DiscountRule> = match path.extension().and_then(|ext| ext.to_str()) {
+        Some("json") => serde_json::from_str(&contents)
+            .with_context(|| format!("failed to parse {} as JSON", path.display()))?,
+        _ => toml::from_str::<TomlRules>(&contents)
+            .with_context(|| format!("failed to parse {} as TOML", path.display()))?
+            .rule,
+    };
+
+    info!(
+        "Loaded {} discount rule(s) from {}",
+        rules.len(),
+        path.display()
+    );
+    Ok(rules.into_iter().map(|r| (r.code, r.percentage)).collect())
+}
+
+#[derive(Debug, Deserialize)]
+struct TomlRules {
+    #[serde(default)]
+    rule: Vec<DiscountRule>,
+}
+
+async fn load_from_database(pool: &MySqlPool) -> Result<DiscountRules> {
+    let rows: Vec<(String, f32)> =
+        sqlx::query_as("SELECT code, percentage FROM discount_rules")
+            .fetch_all(pool)
+            .await
+            .context("failed to load discount_rules table")?;
+
+    info!("Loaded {} discount rule(s) from the database", rows.len());
+    Ok(rows.into_iter().collect())
+}
@@ -1,14 +1,82 @@
 use anyhow::Result;
+use clap::Parser;
 use database_common_lib::database_connection::{create_pool, DatabaseConnectionData};
+use discount_rules::DiscountRules;
 use log::*;
 use serde::Deserialize;
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+
+mod discount_rules;
+
+/// Appends Mardens discount pricing to Aubuchon price export CSVs.
+#[derive(Debug, Parser)]
+#[command(author, version, about)]
+struct Cli {
+    /// Path to one or more CSV exports to process.
+    #[arg(short, long = "input", required = true)]
+    inputs: Vec<PathBuf>,
+
+    /// Number of rows to include in each database batch.
+    #[arg(long, default_value_t = 500, value_parser = clap::value_parser!(usize).range(1..))]
+    batch_size: usize,
+
+    /// Name of the database table to update.
+    #[arg(long, default_value = "DQ8weMwxbW", value_parser = validate_table_name)]
+    table: String,
+
+    /// Path to a TOML or JSON file mapping discount codes to percentages.
+    /// When omitted, the `discount_rules` table is read from the database.
+    #[arg(long)]
+    discount_rules: Option<PathBuf>,
+
+    /// Skip rows whose discount code isn't found in the discount rules
+    /// instead of leaving them with no computed Mardens price.
+    #[arg(long)]
+    skip_unknown_discounts: bool,
+
+    /// Maximum number of batch upload queries to run against the database
+    /// at once.
+    #[arg(long, default_value_t = 8)]
+    concurrency: usize,
+
+    /// Validate the input and print a summary report instead of writing
+    /// anything to the database.
+    #[arg(long)]
+    dry_run: bool,
+
+    /// When used with --dry-run, write the rejected rows to this CSV path.
+    #[arg(long)]
+    rejected_output: Option<PathBuf>,
+}
+
+/// Rejects table names that can't be safely interpolated into a
+/// backtick-quoted SQL identifier (e.g. one containing a backtick).
+fn validate_table_name(name: &str) -> std::result::Result<String, String> {
+    if !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        Ok(name.to_string())
+    } else {
+        Err("table name must contain only ASCII letters, digits, and underscores".to_string())
+    }
+}
 
 #[derive(Debug)]
 struct CSVRow {
     upc: String,
+    /// The raw, unparsed `price1` field as it appeared in the CSV, kept so
+    /// a failed parse can be diagnosed instead of just showing the `0.0`
+    /// fallback.
+    raw_price: String,
     retail: f32,
     discount: String,
     mardens_price: Option<f32>,
+    price_parse_failed: bool,
 }
 
 #[tokio::main(flavor = "current_thread")]
@@ -17,14 +85,55 @@ async fn main() -> Result<()> {
         .format_timestamp(None)
         .filter_level(LevelFilter::Debug)
         .init();
+    let cli = Cli::parse();
     info!("Starting...");
     let start_time = chrono::Local::now();
-    let mut csv_values = load_csv_values()?;
+
+    // A dry run backed entirely by a discount-rules file needs no database
+    // connectivity at all; every other path requires the live pool.
+    let pool = if cli.dry_run && cli.discount_rules.is_some() {
+        None
+    } else {
+        let config = DatabaseConnectionData::get().await?;
+        Some(create_pool(&config).await?)
+    };
+    let rules =
+        discount_rules::load_discount_rules(pool.as_ref(), cli.discount_rules.as_deref()).await?;
+
+    let mut csv_values = load_csv_values(&cli.inputs)?;
     debug!("Loaded {} rows.", csv_values.len());
-    debug!("{:?}", csv_values[0]);
-    calculate_mardens_prices(&mut csv_values);
+    if let Some(first) = csv_values.first() {
+        debug!("{:?}", first);
+    }
+    calculate_mardens_prices(&mut csv_values, &rules);
     debug!("Finished calculating mardens prices.");
-    upload_to_database(csv_values).await?;
+
+    if cli.dry_run {
+        // Report on every row exactly as computed, before --skip-unknown-discounts
+        // would otherwise remove the rows this report exists to surface.
+        let (report, rejected) = validate_rows(&csv_values, &rules);
+        info!("Dry run validation report: {:#?}", report);
+        if let Some(path) = &cli.rejected_output {
+            write_rejected_rows(path, &csv_values, &rejected)?;
+            info!("Wrote {} rejected row(s) to {}", rejected.len(), path.display());
+        }
+        return Ok(());
+    }
+
+    if cli.skip_unknown_discounts {
+        csv_values.retain(|row| row.mardens_price.is_some());
+    }
+
+    let pool = pool.expect("database pool is required outside of a file-backed dry run");
+    record_price_history(&pool, &csv_values, cli.batch_size).await?;
+    upload_to_database(
+        &pool,
+        csv_values,
+        &cli.table,
+        cli.batch_size,
+        cli.concurrency,
+    )
+    .await?;
 
     info!(
         "Finished in {:?}",
@@ -33,103 +142,347 @@ async fn main() -> Result<()> {
     Ok(())
 }
 
-fn load_csv_values() -> Result<Vec<CSVRow>> {
+fn load_csv_values(inputs: &[PathBuf]) -> Result<Vec<CSVRow>> {
     let mut results = vec![];
-    let vartext = include_str!("../file.csv");
-    let mut rdr = csv::ReaderBuilder::new()
-    .has_headers(true)
-    .from_reader(vartext.as_bytes());
-    for result in rdr.deserialize::<CSVRow>() {
-        let result = result?;
-        if !result.discount.is_empty() {
-            results.push(result);
+    for path in inputs {
+        debug!("Reading {}", path.display());
+        let file = File::open(path)?;
+        let mut rdr = csv::ReaderBuilder::new()
+            .has_headers(true)
+            .from_reader(BufReader::new(file));
+        for result in rdr.deserialize::<CSVRow>() {
+            results.push(result?);
         }
     }
     Ok(results)
 }
 
-fn calculate_mardens_prices(rows: &mut [CSVRow]) {
+/// Computes each row's Mardens price from its discount code, warning on
+/// (but not dropping) rows whose code isn't in `rules`. Dropping
+/// unknown-code rows is the caller's decision (see `--skip-unknown-discounts`
+/// in `main`), so this always leaves every row in place for other
+/// consumers, like the dry-run report, to see and count.
+fn calculate_mardens_prices(rows: &mut [CSVRow], rules: &DiscountRules) {
     for row in rows.iter_mut() {
-        let code = row.discount.as_str();
-        let percentage: f32 = match code {
-            "a" => 0.4,
-            "b" => 0.45,
-            "c" => 0.5,
-            "d" => 0.6,
-            _ => 0.4,
-        };
-        row.mardens_price = Some(row.retail * (1f32 - percentage));
+        match rules.get(row.discount.as_str()) {
+            Some(&percentage) => row.mardens_price = Some(row.retail * (1f32 - percentage)),
+            None => warn!(
+                "Unknown discount code {:?} for UPC {}",
+                row.discount, row.upc
+            ),
+        }
     }
 }
 
-async fn upload_to_database(rows: Vec<CSVRow>) -> Result<()> {
-    let config = DatabaseConnectionData::get().await?;
-    let pool = create_pool(&config).await?;
-
-    // Set batch size for grouping queries
-    let batch_size = 500; // Adjust based on your database capabilities and data size
-
-    // Process rows in batches
+/// Records each computed row in the append-only `price_history` table,
+/// keeping an audit trail of markdown changes per product.
+///
+/// Requires a unique index on `(prodaltkey, mardens_price)`: a run that
+/// recomputes the same price for a UPC just bumps `last_seen`, while a
+/// changed price inserts a fresh history row.
+async fn record_price_history(
+    pool: &sqlx::MySqlPool,
+    rows: &[CSVRow],
+    batch_size: usize,
+) -> Result<()> {
     for chunk in rows.chunks(batch_size) {
-        // Build a batch query with parameters for all rows in this chunk
+        let valid_rows = chunk.iter().filter(|row| row.mardens_price.is_some());
         let mut query_builder = sqlx::QueryBuilder::new(
-            "UPDATE `DQ8weMwxbW` SET mp = CASE prodaltkey "
+            "INSERT INTO price_history (prodaltkey, retail, discount_code, mardens_price, fetched_at, last_seen) ",
         );
 
-        // Add WHEN/THEN clauses for each row with mardens_price
-        let mut params = Vec::new();
-        let mut discount_updates = Vec::new();
+        let now = chrono::Local::now();
         let mut has_valid_rows = false;
+        query_builder.push_values(valid_rows, |mut b, row| {
+            has_valid_rows = true;
+            b.push_bind(&row.upc)
+                .push_bind(row.retail)
+                .push_bind(&row.discount)
+                .push_bind(row.mardens_price)
+                .push_bind(now)
+                .push_bind(now);
+        });
 
-        for row in chunk {
-            if let Some(mardens_price) = row.mardens_price {
-                has_valid_rows = true;
-                query_builder.push(" WHEN ");
-                query_builder.push_bind(&row.upc);
-                query_builder.push(" THEN ");
-                query_builder.push_bind(mardens_price);
-
-                params.push(&row.upc);
-                discount_updates.push((row.upc.clone(), row.discount.clone()));
-            }
-        }
-
-        // Skip if no valid rows in this batch
         if !has_valid_rows {
             continue;
         }
 
-        // Complete the price update
-        query_builder.push(" END, discount_code = CASE prodaltkey ");
+        query_builder.push(" ON DUPLICATE KEY UPDATE last_seen = VALUES(last_seen)");
+
+        query_builder
+            .build()
+            .execute(pool)
+            .await
+            .map_err(|e| anyhow::anyhow!("Price history insert failed: {}", e))?;
+    }
+
+    Ok(())
+}
+
+/// Row data needed to build a single batch's `UPDATE ... CASE` query,
+/// owned so it can be moved into a spawned task.
+type PendingUpdate = (String, String, f32);
 
-        // Add WHEN/THEN clauses for discount codes
-        for (upc, discount) in &discount_updates {
-            query_builder.push(" WHEN ");
-            query_builder.push_bind(upc);
-            query_builder.push(" THEN ");
-            query_builder.push_bind(discount);
+/// Tallies how a run's rows compared against what's already in the
+/// database, shared across the concurrent upload tasks.
+#[derive(Default)]
+struct UploadSummary {
+    changed: AtomicU64,
+    unchanged: AtomicU64,
+    missing: AtomicU64,
+}
+
+/// Dispatches each chunk's batch update as its own `tokio` task against the
+/// shared pool, capped by `concurrency` so we don't exhaust connections.
+/// On any task's failure, the remaining tasks are cancelled and the first
+/// error is returned.
+async fn upload_to_database(
+    pool: &sqlx::MySqlPool,
+    rows: Vec<CSVRow>,
+    table: &str,
+    batch_size: usize,
+    concurrency: usize,
+) -> Result<()> {
+    let updates: Vec<PendingUpdate> = rows
+        .into_iter()
+        .filter_map(|row| Some((row.upc, row.discount, row.mardens_price?)))
+        .collect();
+
+    let semaphore = Arc::new(Semaphore::new(concurrency.max(1)));
+    let summary = Arc::new(UploadSummary::default());
+    let mut tasks = JoinSet::new();
+
+    for chunk in updates.chunks(batch_size) {
+        let chunk = chunk.to_vec();
+        let pool = pool.clone();
+        let table = table.to_string();
+        let semaphore = semaphore.clone();
+        let summary = summary.clone();
+        tasks.spawn(async move {
+            let _permit = semaphore.acquire_owned().await.expect("semaphore closed");
+            upload_chunk(&pool, &table, &chunk, &summary).await
+        });
+    }
+
+    let mut total_rows_affected = 0u64;
+    while let Some(result) = tasks.join_next().await {
+        match result {
+            Ok(Ok(rows_affected)) => total_rows_affected += rows_affected,
+            Ok(Err(e)) => {
+                tasks.abort_all();
+                return Err(e);
+            }
+            Err(join_error) => {
+                tasks.abort_all();
+                return Err(anyhow::anyhow!("Batch update task failed: {}", join_error));
+            }
         }
+    }
 
-        // Finalize query with WHERE clause for all relevant UPCs
-        query_builder.push(" END WHERE prodaltkey IN (");
-        let mut separated = query_builder.separated(", ");
-        for upc in params {
+    info!(
+        "Finished database upload: {} rows affected ({} changed, {} unchanged, {} missing from `{}`)",
+        total_rows_affected,
+        summary.changed.load(Ordering::Relaxed),
+        summary.unchanged.load(Ordering::Relaxed),
+        summary.missing.load(Ordering::Relaxed),
+        table,
+    );
+    Ok(())
+}
+
+/// True if two Mardens prices differ by more than float rounding noise.
+fn price_changed(current: f32, new: f32) -> bool {
+    (current - new).abs() > 0.001
+}
+
+/// Builds and executes a single `UPDATE ... CASE` batch query, first
+/// dropping any rows whose price and discount code already match what's
+/// in the database so re-runs are idempotent and cheap.
+async fn upload_chunk(
+    pool: &sqlx::MySqlPool,
+    table: &str,
+    chunk: &[PendingUpdate],
+    summary: &UploadSummary,
+) -> Result<u64> {
+    let mut select_builder = sqlx::QueryBuilder::new(format!(
+        "SELECT prodaltkey, mp, discount_code FROM `{table}` WHERE prodaltkey IN ("
+    ));
+    {
+        let mut separated = select_builder.separated(", ");
+        for (upc, _, _) in chunk {
             separated.push_bind(upc);
         }
-        separated.push_unseparated(")");
-
-        // Execute the batch query
-        match query_builder.build().execute(&pool).await {
-            Ok(result) => {
-                info!("Batch update succeeded: {} rows affected", result.rows_affected());
-            },
-            Err(e) => {
-                error!("Error with batch update: {}", e);
-                return Err(anyhow::anyhow!("Database batch update failed: {}", e));
+    }
+    select_builder.push(")");
+
+    let existing: Vec<(String, Option<f32>, Option<String>)> = select_builder
+        .build_query_as()
+        .fetch_all(pool)
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to load existing prices: {}", e))?;
+    let existing: std::collections::HashMap<_, _> = existing
+        .into_iter()
+        .map(|(upc, mp, discount_code)| (upc, (mp, discount_code)))
+        .collect();
+
+    let to_update: Vec<&PendingUpdate> = chunk
+        .iter()
+        .filter(|(upc, discount, mardens_price)| match existing.get(upc) {
+            None => {
+                warn!("UPC {upc} not found in `{table}`, skipping");
+                summary.missing.fetch_add(1, Ordering::Relaxed);
+                false
             }
+            Some((existing_mp, existing_discount)) => {
+                let unchanged = existing_mp.is_some_and(|mp| !price_changed(mp, *mardens_price))
+                    && existing_discount.as_deref() == Some(discount.as_str());
+                if unchanged {
+                    summary.unchanged.fetch_add(1, Ordering::Relaxed);
+                } else {
+                    summary.changed.fetch_add(1, Ordering::Relaxed);
+                }
+                !unchanged
+            }
+        })
+        .collect();
+
+    if to_update.is_empty() {
+        return Ok(0);
+    }
+
+    // Build a batch query with parameters for the changed rows in this chunk
+    let mut query_builder =
+        sqlx::QueryBuilder::new(format!("UPDATE `{table}` SET mp = CASE prodaltkey "));
+
+    // Add WHEN/THEN clauses for each row with mardens_price
+    for (upc, _, mardens_price) in &to_update {
+        query_builder.push(" WHEN ");
+        query_builder.push_bind(upc);
+        query_builder.push(" THEN ");
+        query_builder.push_bind(mardens_price);
+    }
+
+    // Complete the price update
+    query_builder.push(" END, discount_code = CASE prodaltkey ");
+
+    // Add WHEN/THEN clauses for discount codes
+    for (upc, discount, _) in &to_update {
+        query_builder.push(" WHEN ");
+        query_builder.push_bind(upc);
+        query_builder.push(" THEN ");
+        query_builder.push_bind(discount);
+    }
+
+    // Finalize query with WHERE clause for all relevant UPCs
+    query_builder.push(" END WHERE prodaltkey IN (");
+    let mut separated = query_builder.separated(", ");
+    for (upc, _, _) in &to_update {
+        separated.push_bind(upc);
+    }
+    separated.push_unseparated(")");
+
+    // Execute the batch query
+    match query_builder.build().execute(pool).await {
+        Ok(result) => {
+            info!(
+                "Batch update succeeded: {} rows affected",
+                result.rows_affected()
+            );
+            Ok(result.rows_affected())
+        }
+        Err(e) => {
+            error!("Error with batch update: {}", e);
+            Err(anyhow::anyhow!("Database batch update failed: {}", e))
         }
     }
+}
+
+/// Summary of an input file's quality, produced by `--dry-run` instead of
+/// writing anything to the database.
+#[derive(Debug, Default)]
+struct ValidationReport {
+    total_rows: usize,
+    empty_discount_codes: usize,
+    unknown_discount_codes: usize,
+    price_parse_failures: usize,
+    duplicate_upcs: usize,
+    min_price: Option<f32>,
+    max_price: Option<f32>,
+    mean_price: Option<f32>,
+}
+
+/// Validates the loaded rows against the discount rules, returning a
+/// summary report and the indices of rows that should be rejected.
+fn validate_rows(rows: &[CSVRow], rules: &DiscountRules) -> (ValidationReport, Vec<usize>) {
+    let mut report = ValidationReport {
+        total_rows: rows.len(),
+        ..Default::default()
+    };
+    let mut rejected = Vec::new();
+    let mut seen_upcs = HashSet::new();
+    let mut prices = Vec::new();
+
+    for (index, row) in rows.iter().enumerate() {
+        let mut reject = false;
+
+        if row.discount.is_empty() {
+            report.empty_discount_codes += 1;
+            reject = true;
+        } else if !rules.contains_key(row.discount.as_str()) {
+            report.unknown_discount_codes += 1;
+            reject = true;
+        }
+
+        if row.price_parse_failed {
+            report.price_parse_failures += 1;
+            reject = true;
+        }
 
+        if !seen_upcs.insert(row.upc.as_str()) {
+            report.duplicate_upcs += 1;
+            reject = true;
+        }
+
+        if let Some(price) = row.mardens_price {
+            prices.push(price);
+        }
+
+        if reject {
+            rejected.push(index);
+        }
+    }
+
+    if !prices.is_empty() {
+        report.min_price = prices.iter().copied().fold(f32::INFINITY, f32::min).into();
+        report.max_price = prices
+            .iter()
+            .copied()
+            .fold(f32::NEG_INFINITY, f32::max)
+            .into();
+        report.mean_price = Some(prices.iter().sum::<f32>() / prices.len() as f32);
+    }
+
+    (report, rejected)
+}
+
+/// Writes the rejected rows from a dry run out to a CSV so staff can
+/// inspect them before pushing a corrected export live.
+fn write_rejected_rows(path: &std::path::Path, rows: &[CSVRow], rejected: &[usize]) -> Result<()> {
+    let mut writer = csv::Writer::from_path(path)?;
+    writer.write_record(["PRODALTKEY", "price1", "discount_code", "mardens_price"])?;
+    for &index in rejected {
+        let row = &rows[index];
+        writer.write_record([
+            row.upc.as_str(),
+            row.raw_price.as_str(),
+            row.discount.as_str(),
+            &row
+                .mardens_price
+                .map(|price| price.to_string())
+                .unwrap_or_default(),
+        ])?;
+    }
+    writer.flush()?;
     Ok(())
 }
 
@@ -153,19 +506,29 @@ impl<'de> Deserialize<'de> for CSVRow {
             e
         })?;
 
-        let retail = helper
+        let digits = helper
             .retail
             .chars()
             .filter(|c| c.is_ascii_digit() || *c == '.')
-            .collect::<String>()
-            .parse::<f32>()
-            .unwrap_or(0.0);
+            .collect::<String>();
+        let (retail, price_parse_failed) = match digits.parse::<f32>() {
+            Ok(value) => (value, false),
+            Err(_) => {
+                warn!(
+                    "Failed to parse price1 {:?} for UPC {}, defaulting to 0.0",
+                    helper.retail, helper.upc
+                );
+                (0.0, true)
+            }
+        };
 
         Ok(CSVRow {
             upc: helper.upc,
+            raw_price: helper.retail,
             retail,
             discount: helper.discount,
             mardens_price: None,
+            price_parse_failed,
         })
     }
 }